@@ -4,17 +4,17 @@
 //                    storage lifecycle policies.
 //
 // Copyright (C) 2023 Jonathan Davies
-// 
+//
 // Permission is hereby granted, free of charge, to any person obtaining a copy
 // of this software and associated documentation files (the "Software"), to deal
 // in the Software without restriction, including without limitation the rights
 // to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
 // copies of the Software, and to permit persons to whom the Software is
 // furnished to do so, subject to the following conditions:
-// 
+//
 // The above copyright notice and this permission notice shall be included in all
 // copies or substantial portions of the Software.
-// 
+//
 // THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
 // IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
 // FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
@@ -27,10 +27,19 @@
 #![allow(clippy::result_large_err)]
 
 use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::{config::Region, Client, Error};
+use aws_sdk_s3::{
+    config::Region,
+    types::{Delete, ObjectIdentifier},
+    Client, Error,
+};
 use aws_smithy_types_convert::date_time::DateTimeExt;
-use chrono::{Days, Utc};
+use chrono::{DateTime, Days, Utc};
 use clap::Parser;
+use futures::stream::StreamExt;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
 
 #[derive(Debug, Parser)]
 struct Opt {
@@ -38,9 +47,11 @@ struct Opt {
     #[structopt(short, long)]
     bucket: String,
 
-    /// Number of days to wait for
+    /// Number of days to wait for. Required unless `--config`, `--expire-date`
+    /// or `--versions` with `--noncurrent-days` is given; not allowed together
+    /// with `--expire-date`.
     #[structopt(short, long)]
-    days: u64,
+    days: Option<u64>,
 
     /// Whether to look for, but not delete objects
     #[structopt(long)]
@@ -53,6 +64,310 @@ struct Opt {
     /// The AWS Region.
     #[structopt(short, long, env = "AWS_DEFAULT_REGION")]
     region: Option<String>,
+
+    /// Number of keys to delete per DeleteObjects request (clamped to 1000)
+    #[structopt(long, default_value = "1000")]
+    batch_size: usize,
+
+    /// Path to a TOML file of per-prefix retention rules.
+    #[structopt(long)]
+    config: Option<String>,
+
+    /// Absolute RFC 3339 cutoff, mutually exclusive with `--days`. Must be
+    /// at 00:00:00 GMT.
+    #[structopt(long)]
+    expire_date: Option<DateTime<Utc>>,
+
+    /// Treat the bucket as version-enabled and expire noncurrent versions.
+    #[structopt(long)]
+    versions: bool,
+
+    /// Retention, in days, for noncurrent versions. Defaults to `--days`.
+    #[structopt(long)]
+    noncurrent_days: Option<u64>,
+
+    /// Abort incomplete multipart uploads older than this many days.
+    #[structopt(long)]
+    abort_multipart_days: Option<u64>,
+}
+
+/// A single per-prefix retention rule loaded from `--config`.
+#[derive(Debug, Deserialize)]
+struct Rule {
+    prefix: String,
+    days: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct Config {
+    #[serde(rename = "rule")]
+    rules: Vec<Rule>,
+}
+
+fn load_config(path: &Path) -> Config {
+    let contents =
+        fs::read_to_string(path).unwrap_or_else(|e| panic!("Failed to read {path:?}: {e}"));
+    toml::from_str(&contents).expect("Failed to parse config file")
+}
+
+/// Flushes a batch via a single `DeleteObjects` request, returning the keys
+/// that failed to delete.
+async fn flush_batch(
+    client: &Client,
+    bucket: &str,
+    batch: &mut Vec<ObjectIdentifier>,
+    dry_run: bool,
+) -> Result<Vec<String>, Error> {
+    if batch.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    if dry_run {
+        for object_id in batch.iter() {
+            println!("{} would be deleted", object_id.key());
+        }
+        batch.clear();
+        return Ok(Vec::new());
+    }
+
+    let delete = Delete::builder()
+        .set_objects(Some(std::mem::take(batch)))
+        .build()
+        .expect("Failed to build Delete payload");
+
+    let resp = client
+        .delete_objects()
+        .bucket(bucket)
+        .delete(delete)
+        .send()
+        .await?;
+
+    for deleted in resp.deleted() {
+        println!("{} deleted", deleted.key().unwrap_or_default());
+    }
+
+    let mut failed_keys = Vec::new();
+
+    for error in resp.errors() {
+        eprintln!(
+            "failed to delete {}: {} ({})",
+            error.key().unwrap_or_default(),
+            error.message().unwrap_or_default(),
+            error.code().unwrap_or_default()
+        );
+
+        failed_keys.push(error.key().unwrap_or_default().to_string());
+    }
+
+    Ok(failed_keys)
+}
+
+/// Expires objects under `prefix` (the whole bucket when `None`) older than
+/// `expiry`, skipping any key under `exclude_prefixes`.
+async fn expire_prefix(
+    client: &Client,
+    bucket: &str,
+    prefix: Option<&str>,
+    exclude_prefixes: &[String],
+    expiry: DateTime<Utc>,
+    batch_size: usize,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let mut pages = client
+        .list_objects_v2()
+        .bucket(bucket)
+        .set_prefix(prefix.map(String::from))
+        .into_paginator()
+        .send();
+
+    let mut batch: Vec<ObjectIdentifier> = Vec::with_capacity(batch_size);
+
+    while let Some(page) = pages.next().await {
+        let page = page?;
+
+        for object in page.contents() {
+            let key = object.key().unwrap_or_default();
+
+            if exclude_prefixes
+                .iter()
+                .any(|excluded| key.starts_with(excluded))
+            {
+                continue;
+            }
+
+            let object_timestamp: chrono::DateTime<Utc> = object
+                .last_modified
+                .expect("Object does not have a last modified metadata entry")
+                .to_chrono_utc()
+                .expect("Error converting last modified datetime");
+
+            if object_timestamp < expiry {
+                println!(
+                    "{} is older than the expiry cutoff, marking for deletion...",
+                    object.key().unwrap_or_default(),
+                );
+
+                let object_id = ObjectIdentifier::builder()
+                    .set_key(object.key().map(String::from))
+                    .build()
+                    .expect("Failed to build ObjectIdentifier");
+
+                batch.push(object_id);
+
+                if batch.len() >= batch_size {
+                    flush_batch(client, bucket, &mut batch, dry_run).await?;
+                }
+            }
+        }
+    }
+
+    flush_batch(client, bucket, &mut batch, dry_run).await?;
+    Ok(())
+}
+
+/// Whether a key's latest delete marker is orphaned and should be removed:
+/// true only when no noncurrent versions remain under the key and its
+/// version deletions this run didn't fail.
+fn is_orphan_marker(remaining_noncurrent: u64, key_had_failed_delete: bool) -> bool {
+    remaining_noncurrent == 0 && !key_had_failed_delete
+}
+
+/// Expires noncurrent versions older than `noncurrent_expiry` and cleans up
+/// delete markers left orphaned once their key has no noncurrent versions.
+async fn expire_versions(
+    client: &Client,
+    bucket: &str,
+    noncurrent_expiry: DateTime<Utc>,
+    batch_size: usize,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let mut pages = client
+        .list_object_versions()
+        .bucket(bucket)
+        .into_paginator()
+        .send();
+
+    let mut batch: Vec<ObjectIdentifier> = Vec::with_capacity(batch_size);
+    let mut remaining_noncurrent: HashMap<String, u64> = HashMap::new();
+    let mut failed_keys: HashSet<String> = HashSet::new();
+    let mut latest_delete_markers: Vec<(String, String)> = Vec::new();
+
+    while let Some(page) = pages.next().await {
+        let page = page?;
+
+        for version in page.versions() {
+            if version.is_latest().unwrap_or(false) {
+                continue;
+            }
+
+            let key = version.key().unwrap_or_default().to_string();
+            let version_id = version.version_id().unwrap_or_default().to_string();
+            let last_modified: chrono::DateTime<Utc> = version
+                .last_modified
+                .expect("Version does not have a last modified metadata entry")
+                .to_chrono_utc()
+                .expect("Error converting last modified datetime");
+
+            if last_modified < noncurrent_expiry {
+                println!("{key} (version {version_id}) is a noncurrent version older than the expiry cutoff, marking for deletion...");
+
+                let object_id = ObjectIdentifier::builder()
+                    .set_key(Some(key))
+                    .set_version_id(Some(version_id))
+                    .build()
+                    .expect("Failed to build ObjectIdentifier");
+
+                batch.push(object_id);
+
+                if batch.len() >= batch_size {
+                    failed_keys.extend(flush_batch(client, bucket, &mut batch, dry_run).await?);
+                }
+            } else {
+                *remaining_noncurrent.entry(key).or_insert(0) += 1;
+            }
+        }
+
+        for marker in page.delete_markers() {
+            if marker.is_latest().unwrap_or(false) {
+                latest_delete_markers.push((
+                    marker.key().unwrap_or_default().to_string(),
+                    marker.version_id().unwrap_or_default().to_string(),
+                ));
+            }
+        }
+    }
+
+    failed_keys.extend(flush_batch(client, bucket, &mut batch, dry_run).await?);
+
+    for (key, version_id) in latest_delete_markers {
+        let remaining = remaining_noncurrent.get(&key).copied().unwrap_or(0);
+
+        if is_orphan_marker(remaining, failed_keys.contains(&key)) {
+            println!("{key} (delete marker {version_id}) is an orphan delete marker, marking for deletion...");
+
+            let object_id = ObjectIdentifier::builder()
+                .set_key(Some(key))
+                .set_version_id(Some(version_id))
+                .build()
+                .expect("Failed to build ObjectIdentifier");
+
+            batch.push(object_id);
+
+            if batch.len() >= batch_size {
+                flush_batch(client, bucket, &mut batch, dry_run).await?;
+            }
+        }
+    }
+
+    flush_batch(client, bucket, &mut batch, dry_run).await?;
+    Ok(())
+}
+
+/// Aborts incomplete multipart uploads initiated before `expiry`.
+async fn abort_multipart_uploads(
+    client: &Client,
+    bucket: &str,
+    expiry: DateTime<Utc>,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let mut pages = client
+        .list_multipart_uploads()
+        .bucket(bucket)
+        .into_paginator()
+        .send();
+
+    while let Some(page) = pages.next().await {
+        let page = page?;
+
+        for upload in page.uploads() {
+            let initiated: chrono::DateTime<Utc> = upload
+                .initiated
+                .expect("Upload does not have an initiated metadata entry")
+                .to_chrono_utc()
+                .expect("Error converting initiated datetime");
+
+            if initiated < expiry {
+                let key = upload.key().unwrap_or_default();
+                let upload_id = upload.upload_id().unwrap_or_default();
+
+                println!(
+                    "Incomplete multipart upload {key} ({upload_id}) is older than the expiry cutoff, aborting..."
+                );
+
+                if !dry_run {
+                    client
+                        .abort_multipart_upload()
+                        .bucket(bucket)
+                        .key(key)
+                        .upload_id(upload_id)
+                        .send()
+                        .await?;
+                }
+            }
+        }
+    }
+
+    Ok(())
 }
 
 #[tokio::main]
@@ -63,12 +378,45 @@ async fn main() -> Result<(), Error> {
         dry_run,
         endpoint,
         region,
+        batch_size,
+        config,
+        expire_date,
+        versions,
+        noncurrent_days,
+        abort_multipart_days,
     } = Opt::parse();
 
+    assert!(batch_size > 0, "--batch-size must be greater than 0");
+    let batch_size = batch_size.min(1000);
+
+    assert!(
+        !(config.is_some() && versions),
+        "--config and --versions cannot be combined"
+    );
+    assert!(
+        !(config.is_some() && expire_date.is_some()),
+        "--config and --expire-date cannot be combined"
+    );
+    assert!(
+        !(versions && expire_date.is_some()),
+        "--versions and --expire-date cannot be combined"
+    );
+
+    if let Some(expire_date) = expire_date {
+        assert!(
+            expire_date.time() == chrono::NaiveTime::MIN,
+            "--expire-date must be at 00:00:00 GMT, got {expire_date}"
+        );
+    }
+
+    if config.is_none() && !versions {
+        assert!(
+            days.is_some() != expire_date.is_some(),
+            "Exactly one of --days or --expire-date must be given"
+        );
+    }
+
     let now = Utc::now();
-    let days_expiry = now
-        .checked_sub_days(Days::new(days))
-        .expect("Invalid number of days to subtract");
 
     let region_provider = RegionProviderChain::first_try(region.map(Region::new))
         .or_default_provider()
@@ -81,34 +429,107 @@ async fn main() -> Result<(), Error> {
         .await;
     let client = Client::new(&shared_config);
 
-    let resp = client.list_objects_v2().bucket(&bucket).send().await?;
+    if versions {
+        let noncurrent_expiry = now
+            .checked_sub_days(Days::new(
+                noncurrent_days
+                    .or(days)
+                    .expect("--days or --noncurrent-days is required"),
+            ))
+            .expect("Invalid number of days to subtract");
+
+        expire_versions(&client, &bucket, noncurrent_expiry, batch_size, dry_run).await?;
+    } else if let Some(config_path) = config {
+        let config = load_config(Path::new(&config_path));
+        let rule_prefixes: Vec<String> = config
+            .rules
+            .iter()
+            .map(|rule| rule.prefix.clone())
+            .collect();
 
-    for object in resp.contents().unwrap_or_default() {
-        let object_timestamp: chrono::DateTime<Utc> = object
-            .last_modified
-            .expect("Object does not have a last modified metadata entry")
-            .to_chrono_utc()
-            .expect("Error converting last modified datetime");
+        for rule in &config.rules {
+            let expiry = now
+                .checked_sub_days(Days::new(rule.days))
+                .expect("Invalid number of days to subtract");
 
-        if object_timestamp < days_expiry {
             println!(
-                "{} is older than {} days, deleting...",
-                object.key().unwrap_or_default(),
-                days
+                "Expiring objects under prefix {:?} older than {} days",
+                rule.prefix, rule.days
             );
 
-            if !dry_run {
-                client
-                    .delete_object()
-                    .bucket(&bucket)
-                    .key(object.key().unwrap_or_default())
-                    .send()
-                    .await?;
-            }
+            expire_prefix(
+                &client,
+                &bucket,
+                Some(&rule.prefix),
+                &[],
+                expiry,
+                batch_size,
+                dry_run,
+            )
+            .await?;
+        }
 
-            println!("{} deleted", object.key().unwrap_or_default())
+        match days {
+            Some(days) => {
+                let expiry = now
+                    .checked_sub_days(Days::new(days))
+                    .expect("Invalid number of days to subtract");
+
+                println!("Expiring objects outside all configured prefixes older than {days} days");
+
+                expire_prefix(
+                    &client,
+                    &bucket,
+                    None,
+                    &rule_prefixes,
+                    expiry,
+                    batch_size,
+                    dry_run,
+                )
+                .await?;
+            }
+            None => eprintln!(
+                "Warning: no --days given alongside --config; objects outside all configured prefixes will not be expired"
+            ),
         }
+    } else {
+        let expiry = match expire_date {
+            Some(expire_date) => expire_date,
+            None => now
+                .checked_sub_days(Days::new(days.expect("--days is required")))
+                .expect("Invalid number of days to subtract"),
+        };
+
+        expire_prefix(&client, &bucket, None, &[], expiry, batch_size, dry_run).await?;
+    }
+
+    if let Some(abort_multipart_days) = abort_multipart_days {
+        let abort_expiry = now
+            .checked_sub_days(Days::new(abort_multipart_days))
+            .expect("Invalid number of days to subtract");
+
+        abort_multipart_uploads(&client, &bucket, abort_expiry, dry_run).await?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::is_orphan_marker;
+
+    #[test]
+    fn keeps_marker_with_remaining_noncurrent_versions() {
+        assert!(!is_orphan_marker(1, false));
+    }
+
+    #[test]
+    fn removes_marker_once_all_versions_expired() {
+        assert!(is_orphan_marker(0, false));
+    }
+
+    #[test]
+    fn keeps_marker_when_a_version_delete_failed() {
+        assert!(!is_orphan_marker(0, true));
+    }
+}